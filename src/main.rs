@@ -1,17 +1,21 @@
 use std::cmp::min;
 use std::collections::HashMap;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::Path;
+use std::time::UNIX_EPOCH;
 
 use clap::Parser;
 use clap::ValueEnum;
 use colored::*;
-use glob::glob;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 enum SourceType {
     Java,
     Cpp,
@@ -30,13 +34,164 @@ impl std::fmt::Display for SourceType {
     }
 }
 
+struct CommentSyntax {
+    line: &'static str,
+    block_start: Option<&'static str>,
+    block_end: Option<&'static str>,
+}
+
+impl SourceType {
+    fn extension(&self) -> &'static str {
+        match self {
+            SourceType::Java => "java",
+            SourceType::Cpp => "cpp",
+            SourceType::C => "c",
+            SourceType::Rust => "rs",
+            SourceType::Javascript => "js",
+            SourceType::Python => "py",
+        }
+    }
+
+    fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "java" => Some(SourceType::Java),
+            "cpp" | "cc" | "cxx" | "hpp" | "hh" => Some(SourceType::Cpp),
+            "c" | "h" => Some(SourceType::C),
+            "rs" => Some(SourceType::Rust),
+            "js" | "jsx" | "mjs" => Some(SourceType::Javascript),
+            "py" => Some(SourceType::Python),
+            _ => None,
+        }
+    }
+
+    fn comment_syntax(&self) -> CommentSyntax {
+        match self {
+            SourceType::Python => CommentSyntax {
+                line: "#",
+                block_start: None,
+                block_end: None,
+            },
+            _ => CommentSyntax {
+                line: "//",
+                block_start: Some("/*"),
+                block_end: Some("*/"),
+            },
+        }
+    }
+
+    fn keywords(&self) -> &'static [&'static str] {
+        match self {
+            SourceType::Java => &[
+                "abstract", "assert", "boolean", "break", "byte", "case", "catch", "char",
+                "class", "const", "continue", "default", "do", "double", "else", "enum",
+                "extends", "final", "finally", "float", "for", "goto", "if", "implements",
+                "import", "instanceof", "int", "interface", "long", "native", "new", "package",
+                "private", "protected", "public", "return", "short", "static", "strictfp",
+                "super", "switch", "synchronized", "this", "throw", "throws", "transient", "try",
+                "void", "volatile", "while", "true", "false", "null",
+            ],
+            SourceType::Cpp | SourceType::C => &[
+                "auto", "break", "case", "char", "const", "continue", "default", "do", "double",
+                "else", "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long",
+                "register", "restrict", "return", "short", "signed", "sizeof", "static",
+                "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while",
+                "class", "namespace", "template", "public", "private", "protected", "virtual",
+                "new", "delete", "this", "true", "false", "nullptr", "using", "try", "catch",
+                "throw", "friend", "operator", "explicit", "constexpr",
+            ],
+            SourceType::Rust => &[
+                "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false",
+                "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+                "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+                "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn",
+            ],
+            SourceType::Javascript => &[
+                "break", "case", "catch", "class", "const", "continue", "debugger", "default",
+                "delete", "do", "else", "export", "extends", "finally", "for", "function", "if",
+                "import", "in", "instanceof", "let", "new", "return", "super", "switch", "this",
+                "throw", "try", "typeof", "var", "void", "while", "with", "yield", "true",
+                "false", "null", "undefined", "async", "await", "of", "static", "get", "set",
+            ],
+            SourceType::Python => &[
+                "False", "None", "True", "and", "as", "assert", "async", "await", "break",
+                "class", "continue", "def", "del", "elif", "else", "except", "finally", "for",
+                "from", "global", "if", "import", "in", "is", "lambda", "nonlocal", "not", "or",
+                "pass", "raise", "return", "try", "while", "with", "yield",
+            ],
+        }
+    }
+
+    // Replaces identifiers/numbers/strings with ID/NUM/STR placeholders.
+    fn normalize_line(&self, line: &str) -> String {
+        let keywords = self.keywords();
+        let chars: Vec<char> = line.chars().collect();
+        let n = chars.len();
+        let mut result = String::with_capacity(n);
+        let mut i = 0;
+        while i < n {
+            let c = chars[i];
+            if c == '"' || c == '\'' {
+                let quote = c;
+                let mut j = i + 1;
+                while j < n {
+                    if chars[j] == '\\' && j + 1 < n {
+                        j += 2;
+                        continue;
+                    }
+                    if chars[j] == quote {
+                        j += 1;
+                        break;
+                    }
+                    j += 1;
+                }
+                result.push_str("STR");
+                i = j;
+            } else if c.is_ascii_digit() {
+                let mut j = i;
+                while j < n && (chars[j].is_alphanumeric() || chars[j] == '.' || chars[j] == '_') {
+                    j += 1;
+                }
+                result.push_str("NUM");
+                i = j;
+            } else if c.is_alphabetic() || c == '_' {
+                let mut j = i;
+                while j < n && (chars[j].is_alphanumeric() || chars[j] == '_') {
+                    j += 1;
+                }
+                let word: String = chars[i..j].iter().collect();
+                if keywords.contains(&word.as_str()) {
+                    result.push_str(&word);
+                } else {
+                    result.push_str("ID");
+                }
+                i = j;
+            } else {
+                result.push(c);
+                i += 1;
+            }
+        }
+        result
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    root: String,
+    #[arg(
+        required = true,
+        num_args = 1..,
+        help = "source roots: folders to scan, or individual files"
+    )]
+    roots: Vec<String>,
 
-    #[arg(help = "source file type")]
-    source_type: SourceType,
+    #[arg(
+        short = 'l',
+        long = "lang",
+        required = true,
+        num_args = 1..,
+        help = "source file types to scan"
+    )]
+    source_types: Vec<SourceType>,
 
     #[arg(
         long,
@@ -64,121 +219,197 @@ struct Args {
 
     #[arg(long, default_value_t = 30, help = "top number of results to list")]
     list_top_result: usize,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "output format"
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "tokenize identifiers/numbers/strings before hashing, to also catch renamed-variable (Type-2) clones"
+    )]
+    normalize: bool,
 }
 
-fn compute_ignore_path(ignore_folders: String, root_folder: &str) -> Vec<String> {
-    let mut glob_path: Vec<String> = Vec::new();
-    for f in ignore_folders.split(",") {
-        glob_path.push(
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    CompactJson,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+struct IgnoreMatcher {
+    ignore: GlobSet,
+    keep: GlobSet,
+}
+
+impl IgnoreMatcher {
+    fn is_ignored(&self, path: &Path) -> bool {
+        self.ignore.is_match(path) && !self.keep.is_match(path)
+    }
+
+    fn has_keep_patterns(&self) -> bool {
+        !self.keep.is_empty()
+    }
+}
+
+// A pattern with no `/` names a file/folder rather than a path, so it's
+// matched at any depth; a pattern containing `/` is honored as-is, plus a
+// variant for its descendants so it can re-include a nested subtree.
+fn expand_ignore_pattern(pattern: &str, root_folder: &str) -> Vec<String> {
+    let rooted = Path::new(root_folder).join(pattern).display().to_string();
+    let descendants = Path::new(root_folder)
+        .join(pattern)
+        .join("**")
+        .display()
+        .to_string();
+    if pattern.contains('/') {
+        vec![rooted, descendants]
+    } else {
+        vec![
+            rooted,
+            descendants,
             Path::new(root_folder)
                 .join("**")
-                .join(f)
+                .join(pattern)
                 .display()
                 .to_string(),
-        );
-        glob_path.push(Path::new(root_folder).join(f).display().to_string());
+            Path::new(root_folder)
+                .join("**")
+                .join(pattern)
+                .join("**")
+                .display()
+                .to_string(),
+        ]
     }
+}
 
-    let mut s: Vec<String> = Vec::new();
-    for p in glob_path {
-        for entry in glob(&p).expect("fail to glob ignore path") {
-            match entry {
-                Ok(path) => {
-                    s.push(path.display().to_string());
-                }
-                Err(e) => {
-                    println!("{:?}", e);
-                }
-            }
+fn add_ignore_pattern(builder: &mut GlobSetBuilder, pattern: &str, root_folder: &str) {
+    for glob_pattern in expand_ignore_pattern(pattern, root_folder) {
+        if let Ok(g) = Glob::new(&glob_pattern) {
+            builder.add(g);
         }
     }
+}
 
-    s
+fn read_gitignore_patterns(root_folder: &str) -> Vec<String> {
+    let gitignore_path = Path::new(root_folder).join(".gitignore");
+    let Ok(contents) = std::fs::read_to_string(gitignore_path) else {
+        return Vec::new();
+    };
+
+    contents
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect()
 }
 
-fn path_starts_with(path: &str, ignore_folders: &Vec<String>) -> bool {
-    for f in ignore_folders {
-        if path.starts_with(f) {
-            return true;
+fn build_ignore_matcher(ignore_folders: &str, root_folder: &str) -> IgnoreMatcher {
+    let mut ignore_builder = GlobSetBuilder::new();
+    let mut keep_builder = GlobSetBuilder::new();
+
+    let patterns = ignore_folders
+        .split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .chain(read_gitignore_patterns(root_folder));
+
+    for pattern in patterns {
+        if let Some(kept) = pattern.strip_prefix('!') {
+            add_ignore_pattern(&mut keep_builder, kept, root_folder);
+        } else {
+            add_ignore_pattern(&mut ignore_builder, &pattern, root_folder);
         }
     }
 
-    false
+    IgnoreMatcher {
+        ignore: ignore_builder
+            .build()
+            .expect("failed to build ignore globset"),
+        keep: keep_builder.build().expect("failed to build keep globset"),
+    }
 }
 
+// Prunes ignored directories without descending into them, unless keep
+// patterns exist; then every directory is walked and ignored files are
+// skipped one by one.
 fn scan_folders(
-    root_path: &Path,
+    dir: &Path,
     source_files: &mut Vec<String>,
     list_source_folder: bool,
-    ignore_folders: &Vec<String>,
-) -> Result<(), glob::PatternError> {
-    for entry in glob(root_path.to_str().unwrap())? {
-        match entry {
-            Ok(path) => {
-                let source_file = path.display().to_string();
-                if path_starts_with(&source_file, &ignore_folders) {
-                    continue;
-                }
-
-                if list_source_folder {
-                    println!("{}", path.display());
-                }
-                source_files.push(source_file);
+    extension: &str,
+    ignore_matcher: &IgnoreMatcher,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ignored = ignore_matcher.is_ignored(&path);
+
+        if path.is_dir() {
+            // A kept subtree can be nested under an ignored directory, so only
+            // prune without descending when nothing could re-include it.
+            if ignored && !ignore_matcher.has_keep_patterns() {
+                continue;
             }
-            Err(e) => println!("{:?}", e),
+            scan_folders(
+                &path,
+                source_files,
+                list_source_folder,
+                extension,
+                ignore_matcher,
+            )?;
+        } else if !ignored && path.extension().and_then(|e| e.to_str()) == Some(extension) {
+            let source_file = path.display().to_string();
+            if list_source_folder {
+                println!("{}", path.display());
+            }
+            source_files.push(source_file);
         }
     }
 
     Ok(())
 }
 
-struct TrieNode {
-    children: HashMap<char, TrieNode>,
-    occurence: usize,
-}
-
-impl TrieNode {
-    fn new() -> Self {
-        Self {
-            children: HashMap::new(),
-            occurence: 0,
-        }
-    }
-
-    fn insert(&mut self, word: &str) -> usize {
-        let mut node = self;
-        for char in word.chars() {
-            let next_node = node.children.entry(char).or_insert(TrieNode::new());
-            node = next_node;
-        }
-
-        node.occurence += 1;
-        node.occurence
-    }
-}
-
 struct CPLocation {
     filepath: String,
     start: usize,
     end: usize,
+    block_key: u64,
 }
 
-fn parse(
-    filepath: &str,
-    root: &mut TrieNode,
-    cp_locations: &mut Vec<CPLocation>,
-    min_line_count: usize,
-    min_char_count: usize,
-) -> io::Result<()> {
+fn hash_line(line: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_block(line_hashes: &[u64]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    line_hashes.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Returns one entry per physical line: `(line, should_index)`.
+fn read_source_lines(filepath: &str, comments: &CommentSyntax) -> io::Result<Vec<(String, bool)>> {
     let file = File::open(filepath)?;
     let mut reader = BufReader::new(file);
 
-    let mut comments = false;
-    let mut cp_found = false;
-    let mut start = 0;
-    let mut end = 0;
-    let mut line_num = 1;
-    let mut char_count = 0;
+    let mut in_comment = false;
+    let mut lines: Vec<(String, bool)> = Vec::new();
     loop {
         let mut line = String::new();
         let len = reader.read_line(&mut line)?;
@@ -188,37 +419,167 @@ fn parse(
 
         line = line.trim().to_string();
 
-        if line.starts_with("/*") {
-            comments = true;
+        if let Some(start) = comments.block_start {
+            if line.starts_with(start) {
+                in_comment = true;
+            }
         }
-        if line.ends_with("*/") {
-            comments = false;
+        if let Some(end) = comments.block_end {
+            if line.ends_with(end) {
+                in_comment = false;
+            }
         }
 
-        if line.starts_with("//") {
-            comments = true;
+        if line.starts_with(comments.line) {
+            in_comment = true;
         }
 
-        let should_index = !comments && !line.is_empty();
+        let should_index = !in_comment && !line.is_empty();
+        lines.push((line, should_index));
+    }
 
-        let next_cp_found;
-        if should_index {
-            let o = root.insert(&line);
-            if o > 1 {
-                next_cp_found = true;
-            } else {
-                next_cp_found = false;
-            }
-        } else {
-            next_cp_found = false;
+    Ok(lines)
+}
+
+// Bump whenever the cached line-hashing rules change.
+const CACHE_VERSION: u32 = 2;
+const CACHE_FILE_NAME: &str = ".cpfinder-cache";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedLine {
+    hash: u64,
+    len: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    mtime: u64,
+    size: u64,
+    lines: Vec<Option<CachedLine>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    version: u32,
+    normalize: bool,
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl Cache {
+    fn empty(normalize: bool) -> Self {
+        Self {
+            version: CACHE_VERSION,
+            normalize,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+fn cache_path(root_folder: &str) -> std::path::PathBuf {
+    Path::new(root_folder).join(CACHE_FILE_NAME)
+}
+
+fn load_cache(root_folder: &str, normalize: bool) -> Cache {
+    let Ok(contents) = std::fs::read_to_string(cache_path(root_folder)) else {
+        return Cache::empty(normalize);
+    };
+
+    match serde_json::from_str::<Cache>(&contents) {
+        Ok(cache) if cache.version == CACHE_VERSION && cache.normalize == normalize => cache,
+        _ => Cache::empty(normalize),
+    }
+}
+
+fn save_cache(root_folder: &str, cache: &Cache) {
+    if let Ok(json) = serde_json::to_string(cache) {
+        let _ = std::fs::write(cache_path(root_folder), json);
+    }
+}
+
+fn file_mtime_and_size(filepath: &str) -> io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(filepath)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((mtime, metadata.len()))
+}
+
+// Reuses the cached scan if mtime/size still match, otherwise re-parses.
+fn scan_file(
+    filepath: &str,
+    source_type: SourceType,
+    normalize: bool,
+    cached: Option<&CacheEntry>,
+) -> io::Result<CacheEntry> {
+    let (mtime, size) = file_mtime_and_size(filepath)?;
+    if let Some(entry) = cached {
+        if entry.mtime == mtime && entry.size == size {
+            return Ok(entry.clone());
         }
+    }
+
+    let lines = read_source_lines(filepath, &source_type.comment_syntax())?
+        .into_iter()
+        .map(|(line, should_index)| {
+            should_index.then(|| {
+                let hashed = if normalize {
+                    source_type.normalize_line(&line)
+                } else {
+                    line.clone()
+                };
+                CachedLine {
+                    hash: hash_line(&hashed),
+                    len: line.len(),
+                }
+            })
+        })
+        .collect();
+
+    Ok(CacheEntry { mtime, size, lines })
+}
+
+// Pass one: fold a file's cached line hashes into the global frequency map.
+fn count_lines(entry: &CacheEntry) -> HashMap<u64, u32> {
+    let mut counts: HashMap<u64, u32> = HashMap::new();
+    for line in entry.lines.iter().flatten() {
+        *counts.entry(line.hash).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Pass two: collect contiguous duplicated runs from the frequency map.
+fn find_cp_locations(
+    filepath: &str,
+    entry: &CacheEntry,
+    global_counts: &HashMap<u64, u32>,
+    min_line_count: usize,
+    min_char_count: usize,
+) -> Vec<CPLocation> {
+    let mut cp_locations: Vec<CPLocation> = Vec::new();
+
+    let mut cp_found = false;
+    let mut start = 0;
+    let mut end = 0;
+    let mut char_count = 0;
+    let mut block_hashes: Vec<u64> = Vec::new();
+    for (line_num, line) in entry.lines.iter().enumerate() {
+        let line_num = line_num + 1;
+
+        let next_cp_found = line
+            .as_ref()
+            .map(|l| global_counts.get(&l.hash).copied().unwrap_or(0) > 1)
+            .unwrap_or(false);
 
         if next_cp_found {
             if !cp_found {
                 start = line_num;
+                block_hashes.clear();
             }
             end = line_num;
-            char_count += line.len();
+            char_count += line.as_ref().unwrap().len;
+            block_hashes.push(line.as_ref().unwrap().hash);
 
             cp_found = true;
         } else {
@@ -229,6 +590,7 @@ fn parse(
                         filepath: filepath.to_string(),
                         start,
                         end,
+                        block_key: hash_block(&block_hashes),
                     })
                 }
             }
@@ -236,62 +598,273 @@ fn parse(
             char_count = 0;
             cp_found = false;
         }
+    }
 
-        line_num += 1;
+    cp_locations
+}
+
+#[derive(Serialize)]
+struct CloneMember {
+    file: String,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Serialize)]
+struct CloneGroup {
+    size: usize,
+    members: Vec<CloneMember>,
+}
+
+// Groups cp_locations by block_key so each clone lists every occurrence.
+fn group_clones(cp_locations: &[CPLocation]) -> Vec<CloneGroup> {
+    let mut by_key: HashMap<u64, Vec<CloneMember>> = HashMap::new();
+    for l in cp_locations {
+        by_key.entry(l.block_key).or_default().push(CloneMember {
+            file: l.filepath.clone(),
+            start: l.start,
+            end: l.end,
+        });
     }
 
-    Ok(())
+    let mut groups: Vec<CloneGroup> = by_key
+        .into_values()
+        .map(|members| CloneGroup {
+            size: members
+                .iter()
+                .map(|m| m.end - m.start + 1)
+                .max()
+                .unwrap_or(0),
+            members,
+        })
+        .collect();
+    groups.sort_by_key(|g| g.size);
+    groups.reverse();
+    groups
+}
+
+struct DiscoveredFile {
+    path: String,
+    source_type: SourceType,
+}
+
+// A file root is scanned directly; a folder root is walked once per
+// requested source type and paired with a root-level cache.
+fn discover_root(
+    root: &str,
+    source_types: &[SourceType],
+    ignore_folders: &str,
+    list_source_folder: bool,
+    normalize: bool,
+) -> (Vec<DiscoveredFile>, Option<(String, Cache)>) {
+    let root_path = Path::new(root);
+
+    if root_path.is_file() {
+        let source_type = root_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(SourceType::from_extension)
+            .filter(|st| source_types.contains(st));
+        let files = match source_type {
+            Some(source_type) => vec![DiscoveredFile {
+                path: root.to_string(),
+                source_type,
+            }],
+            None => Vec::new(),
+        };
+        return (files, None);
+    }
+
+    let ignore_matcher = build_ignore_matcher(ignore_folders, root);
+    let cache = load_cache(root, normalize);
+
+    let mut files = Vec::new();
+    for &source_type in source_types {
+        let mut found: Vec<String> = Vec::new();
+        scan_folders(
+            root_path,
+            &mut found,
+            list_source_folder,
+            source_type.extension(),
+            &ignore_matcher,
+        )
+        .ok();
+        files.extend(found.into_iter().map(|path| DiscoveredFile {
+            path,
+            source_type,
+        }));
+    }
+
+    (files, Some((root.to_string(), cache)))
 }
 
 fn main() {
     let args = Args::parse();
 
-    let root_folder = args.root;
-    let root_path = Path::new(&root_folder).join(format!("**/*.{}", args.source_type.to_string()));
-    // println!("{}", root_path.display());
-
-    let ignore_folders = compute_ignore_path(args.ignore_folders, &root_folder);
-    // println!("ignore folders:");
-    // for f in &ignore_folders {
-    //     println!("{}", f);
-    // }
-
-    let mut source_files: Vec<String> = Vec::new();
-    scan_folders(
-        &root_path,
-        &mut source_files,
-        args.list_source_folder,
-        &ignore_folders,
-    )
-    .ok();
-
-    let n = source_files.len();
-    println!("found {} source files of java", n);
-
-    let mut root = TrieNode::new();
-    let mut cp_locations: Vec<CPLocation> = Vec::new();
-    if n > 0 {
-        for i in 0..n {
-            parse(
-                &source_files[i],
-                &mut root,
-                &mut cp_locations,
+    let mut source_files: Vec<DiscoveredFile> = Vec::new();
+    let mut root_caches: HashMap<String, Cache> = HashMap::new();
+    for root in &args.roots {
+        let (files, cache) = discover_root(
+            root,
+            &args.source_types,
+            &args.ignore_folders,
+            args.list_source_folder,
+            args.normalize,
+        );
+        source_files.extend(files);
+        if let Some((root, cache)) = cache {
+            root_caches.insert(root, cache);
+        }
+    }
+
+    println!("found {} source files", source_files.len());
+
+    // Reuse cached line hashes for files whose mtime/size haven't changed,
+    // only re-reading files that actually changed. Every root's files land
+    // in the same frequency index, so duplication is caught across roots
+    // and across languages.
+    let scanned: HashMap<String, CacheEntry> = source_files
+        .par_iter()
+        .filter_map(|f| {
+            let cached = root_caches
+                .values()
+                .find_map(|cache| cache.entries.get(&f.path));
+            scan_file(&f.path, f.source_type, args.normalize, cached)
+                .ok()
+                .map(|entry| (f.path.clone(), entry))
+        })
+        .collect();
+
+    // Pass one: build the global line-frequency map in parallel.
+    let global_counts: HashMap<u64, u32> =
+        scanned
+            .par_iter()
+            .map(|(_, entry)| count_lines(entry))
+            .reduce(HashMap::new, |mut a, b| {
+                for (hash, count) in b {
+                    *a.entry(hash).or_insert(0) += count;
+                }
+                a
+            });
+
+    // Pass two: re-walk each file in parallel and collect duplicated ranges.
+    let mut cp_locations: Vec<CPLocation> = scanned
+        .par_iter()
+        .flat_map(|(filepath, entry)| {
+            find_cp_locations(
+                filepath,
+                entry,
+                &global_counts,
                 args.min_line_count,
                 args.min_char_count,
             )
-            .ok();
+        })
+        .collect();
+
+    // Persist one updated cache per scanned root, keyed by which of its
+    // files actually showed up in this run.
+    for root in root_caches.keys() {
+        let entries: HashMap<String, CacheEntry> = scanned
+            .iter()
+            .filter(|(path, _)| Path::new(path.as_str()).starts_with(root))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        save_cache(
+            root,
+            &Cache {
+                version: CACHE_VERSION,
+                normalize: args.normalize,
+                entries,
+            },
+        );
+    }
+
+    // Sort longest block first; break ties on filepath/start so output is
+    // reproducible across runs instead of depending on HashMap iteration order.
+    cp_locations.sort_by(|a, b| {
+        let len_a = a.end - a.start + 1;
+        let len_b = b.end - b.start + 1;
+        len_b
+            .cmp(&len_a)
+            .then_with(|| a.filepath.cmp(&b.filepath))
+            .then_with(|| a.start.cmp(&b.start))
+    });
+
+    match args.format {
+        OutputFormat::Text => {
+            println!("top {} result:", args.list_top_result.to_string().blue());
+            for l in &cp_locations[0..min(cp_locations.len(), args.list_top_result)] {
+                println!(
+                    "{}: line {}~{}",
+                    l.filepath.red(),
+                    l.start.to_string().purple(),
+                    l.end.to_string().purple()
+                );
+            }
+        }
+        OutputFormat::Json | OutputFormat::CompactJson => {
+            let mut groups = group_clones(&cp_locations);
+            groups.truncate(args.list_top_result);
+
+            let output = if args.format == OutputFormat::CompactJson {
+                serde_json::to_string(&groups)
+            } else {
+                serde_json::to_string_pretty(&groups)
+            };
+            if let Ok(output) = output {
+                println!("{}", output);
+            }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_bare_name_at_any_depth() {
+        let matcher = build_ignore_matcher("node_modules", "root");
+        assert!(matcher.is_ignored(Path::new("root/node_modules/foo.js")));
+        assert!(matcher.is_ignored(Path::new("root/src/node_modules/foo.js")));
+        assert!(!matcher.is_ignored(Path::new("root/src/foo.js")));
+    }
 
-    cp_locations.sort_by_key(|l| l.end - l.start + 1);
-    cp_locations.reverse();
-    println!("top {} result:", args.list_top_result.to_string().blue());
-    for l in &cp_locations[0..min(cp_locations.len(), args.list_top_result)] {
-        println!(
-            "{}: line {}~{}",
-            l.filepath.red(),
-            l.start.to_string().purple(),
-            l.end.to_string().purple()
-        );
+    #[test]
+    fn ignores_extension_glob_at_any_depth() {
+        let matcher = build_ignore_matcher("*.generated.cpp", "root");
+        assert!(matcher.is_ignored(Path::new("root/foo.generated.cpp")));
+        assert!(matcher.is_ignored(Path::new("root/src/foo.generated.cpp")));
+        assert!(!matcher.is_ignored(Path::new("root/src/foo.cpp")));
+    }
+
+    #[test]
+    fn honors_anchored_glob_as_given() {
+        let matcher = build_ignore_matcher("build/**/gen", "root");
+        assert!(matcher.is_ignored(Path::new("root/build/a/gen")));
+        assert!(!matcher.is_ignored(Path::new("root/other/a/gen")));
+    }
+
+    #[test]
+    fn negation_re_includes_kept_path() {
+        let matcher = build_ignore_matcher("build,!build/keep", "root");
+        assert!(matcher.is_ignored(Path::new("root/build/output.o")));
+        assert!(!matcher.is_ignored(Path::new("root/build/keep/output.o")));
+    }
+
+    #[test]
+    fn scan_folders_descends_into_kept_subtree_of_an_ignored_directory() {
+        let root = std::env::temp_dir().join("cpfinder-test-scan-folders-keep");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("build/keep")).unwrap();
+        std::fs::write(root.join("build/output.rs"), "fn a() {}").unwrap();
+        std::fs::write(root.join("build/keep/output.rs"), "fn b() {}").unwrap();
+
+        let matcher = build_ignore_matcher("build,!build/keep", root.to_str().unwrap());
+        let mut source_files = Vec::new();
+        scan_folders(&root, &mut source_files, false, "rs", &matcher).unwrap();
+
+        assert_eq!(source_files, vec![root.join("build/keep/output.rs").display().to_string()]);
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }